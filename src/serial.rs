@@ -0,0 +1,104 @@
+
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::vga_buffer::Port;
+
+//COM1 lives at port base 0x3f8. the 16550 exposes its registers as offsets
+//from that base; the ones we touch are named below.
+const COM1: u16 = 0x3f8;
+
+
+//a tiny driver for the 16550 UART on COM1. running QEMU with `-serial stdio`
+//routes whatever we write here to the host console, which is how the
+//integration tests observe kernel output.
+pub struct SerialPort {
+    data: Port,          // +0 transmit/receive (and divisor low with DLAB set)
+    int_en: Port,        // +1 interrupt enable (and divisor high with DLAB set)
+    fifo_ctrl: Port,     // +2 FIFO control
+    line_ctrl: Port,     // +3 line control (DLAB lives in bit 7)
+    modem_ctrl: Port,    // +4 modem control
+    line_status: Port,   // +5 line status
+}
+
+impl SerialPort {
+    const fn new(base: u16) -> SerialPort {
+        SerialPort {
+            data: Port::new(base),
+            int_en: Port::new(base + 1),
+            fifo_ctrl: Port::new(base + 2),
+            line_ctrl: Port::new(base + 3),
+            modem_ctrl: Port::new(base + 4),
+            line_status: Port::new(base + 5),
+        }
+    }
+
+    //bring the UART up for 38400 baud, 8N1, with the FIFO enabled
+    fn init(&mut self) {
+        unsafe {
+            self.int_en.write(0x00);     // disable interrupts
+            self.line_ctrl.write(0x80);  // set DLAB to expose the divisor
+            self.data.write(0x03);       // divisor low  -> 38400 baud
+            self.int_en.write(0x00);     // divisor high
+            self.line_ctrl.write(0x03);  // clear DLAB, 8 bits, no parity, 1 stop
+            self.fifo_ctrl.write(0xc7);  // enable FIFO, clear, 14-byte threshold
+            self.modem_ctrl.write(0x0b); // DTR, RTS, OUT2
+        }
+    }
+
+    //the transmitter is ready once bit 5 of the line-status register is set
+    fn is_transmit_empty(&mut self) -> bool {
+        unsafe { self.line_status.read() & 0x20 != 0 }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        while !self.is_transmit_empty() {}
+        unsafe { self.data.write(byte) }
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+//global COM1 writer, initialized on first use like WRITER
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        let mut serial = SerialPort::new(COM1);
+        serial.init();
+        Mutex::new(serial)
+    };
+}
+
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($x: tt)*) => (
+        $crate::serial::_print(format_args!($($x)*))
+    );
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($x: tt)*) => (
+        $crate::serial_print!("{}\n",format_args!($($x)*))
+    );
+}
+
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    SERIAL1.lock().write_fmt(args).unwrap();
+}
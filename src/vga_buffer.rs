@@ -1,9 +1,34 @@
 
 use volatile::Volatile;
+use core::arch::asm;
 use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+
+//minimal x86_64-style port wrapper so we can talk to the VGA CRT controller
+//(and, later, other hardware) without pulling in an external crate. reads and
+//writes are unsafe because the effect depends entirely on which port is named.
+pub(crate) struct Port {
+    port: u16,
+}
+
+impl Port {
+    pub(crate) const fn new(port: u16) -> Port {
+        Port { port }
+    }
+
+    pub(crate) unsafe fn write(&mut self, value: u8) {
+        asm!("out dx, al", in("dx") self.port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+
+    pub(crate) unsafe fn read(&mut self) -> u8 {
+        let value: u8;
+        asm!("in al, dx", out("al") value, in("dx") self.port, options(nomem, nostack, preserves_flags));
+        value
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -34,8 +59,12 @@ struct ColorCode(u8);
 
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
-        ColorCode((background as u8)  << 4 |(  foreground as u8))
+    //the blink flag ORs bit 7 of the attribute byte (bit 15 of the cell). that
+    //bit doubles as the top background-colour bit, so with blinking enabled the
+    //background is limited to the first eight colours.
+    const fn new(foreground: Color, background: Color, blink: bool) -> ColorCode {
+        let blink_bit = if blink { 0x80 } else { 0 };
+        ColorCode(blink_bit | (background as u8)  << 4 |(  foreground as u8))
     }
 }
 
@@ -48,8 +77,25 @@ struct ScreenChar {
     color_code: ColorCode
 }
 
-const BUFFER_WIDTH: usize = 25;
-const BUFFER_HEIGHT: usize = 80;
+//VGA text mode is 80 columns wide by 25 rows tall
+const BUFFER_WIDTH: usize = 80;
+const BUFFER_HEIGHT: usize = 25;
+
+//how many completed rows the history ring holds. new_line pushes every
+//finished line here; the newest ones fill the visible rows above the live
+//line and the rest are kept off-screen instead of being discarded
+const SCROLLBACK_ROWS: usize = 256;
+
+//a single row's worth of cells, the unit the scrollback stores and the render
+//loop copies onto the VGA buffer
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+//default blank cell (yellow-on-black space) used to seed the scrollback and
+//live row at startup
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode::new(Color::Yellow, Color::Black, false),
+};
 
 #[repr(transparent)]
 struct Buffer {
@@ -60,74 +106,451 @@ struct Buffer {
 }
 
 
+//the escape parser is a tiny state machine: we sit in Ground until we see
+//an ESC (0x1b), move to Escape to look for the '[' that opens a CSI, then
+//collect the parameter bytes in CsiParams until a final letter arrives.
+//keeping the state on the Writer means a sequence split across several
+//write_byte/write_string calls still parses correctly.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ParseState {
+    Ground,
+    Escape,
+    CsiParams,
+}
+
+
 pub struct Writer {
     column_position: usize,
+    row_position: usize,
     color_code: ColorCode,
+    foreground: Color,
+    background: Color,
+    parse_state: ParseState,
+    params: [u8; 16],
+    params_len: usize,
+    blink: bool,
+    //the live bottom line is edited here and mirrored to the screen; on
+    //new_line it is pushed into `history` and a fresh blank row starts.
+    current_row: Row,
+    //ring buffer of completed rows, both those visible above the live line
+    //and those scrolled off the top
+    history: [Row; SCROLLBACK_ROWS],
+    history_start: usize,
+    history_len: usize,
+    //how many rows the viewport is scrolled up into history; 0 == live output
+    view_offset: usize,
     buffer: &'static mut Buffer,
 }
 
 
 impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => {
-                self.new_line()
+        //any fresh output snaps the viewport back to the live bottom
+        self.scroll_to_bottom();
+        match self.parse_state {
+            ParseState::Ground => match byte {
+                0x1b => self.parse_state = ParseState::Escape,
+                b'\n' => self.new_line(),
+                //bytes reaching here are already final code page 437 codes
+                //(write_string does the UTF-8 translation), so they go
+                //straight to the screen
+                byte => self.put_byte(byte),
+            },
+            ParseState::Escape => {
+                if byte == b'[' {
+                    self.params_len = 0;
+                    self.parse_state = ParseState::CsiParams;
+                } else {
+                    //anything other than '[' after ESC is malformed
+                    self.parse_state = ParseState::Ground;
+                    self.put_byte(0xfe);
+                }
             }
-            byte => {
-                if self.column_position >= BUFFER_WIDTH{
-                    self.new_line();
+            ParseState::CsiParams => match byte {
+                b'0'..=b'9' | b';' => {
+                    if self.params_len < self.params.len() {
+                        self.params[self.params_len] = byte;
+                        self.params_len += 1;
+                    }
+                }
+                0x40..=0x7e => {
+                    self.parse_state = ParseState::Ground;
+                    self.handle_csi(byte);
                 }
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar{
-                    ascii_character: byte,
-                    color_code
-                });
-                self.column_position += 1;
+                _ => {
+                    self.parse_state = ParseState::Ground;
+                    self.put_byte(0xfe);
+                }
+            },
+        }
+        self.update_cursor();
+    }
+
+    //push the current cursor cell to the CRTC. the controller takes a linear
+    //offset split across two index registers: 0x0e holds the high byte and
+    //0x0f the low byte, each written to the data port (0x3d5) after selecting
+    //it through the index port (0x3d4).
+    pub fn update_cursor(&mut self) {
+        let pos = self.row_position * BUFFER_WIDTH + self.column_position;
+        let mut index = Port::new(0x3d4);
+        let mut data = Port::new(0x3d5);
+        unsafe {
+            index.write(0x0e);
+            data.write(((pos >> 8) & 0xff) as u8);
+            index.write(0x0f);
+            data.write((pos & 0xff) as u8);
+        }
+    }
+
+    //turn the blinking hardware cursor on, spanning scanlines 0..=15 of the
+    //character cell. register 0x0a holds the start scanline (bit 5 also gates
+    //the cursor), 0x0b the end scanline.
+    pub fn enable_cursor(&mut self) {
+        let mut index = Port::new(0x3d4);
+        let mut data = Port::new(0x3d5);
+        unsafe {
+            index.write(0x0a);
+            let start = data.read() & 0xc0;
+            data.write(start);
+            index.write(0x0b);
+            let end = data.read() & 0xe0;
+            data.write(end | 15);
+        }
+    }
+
+    //bit 5 of register 0x0a hides the cursor
+    pub fn disable_cursor(&mut self) {
+        let mut index = Port::new(0x3d4);
+        let mut data = Port::new(0x3d5);
+        unsafe {
+            index.write(0x0a);
+            data.write(0x20);
+        }
+    }
+
+    //place the cursor anywhere on screen; used by escape-sequence handling and
+    //usable by a future shell. coordinates are clamped to the buffer.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.row_position = core::cmp::min(row, BUFFER_HEIGHT - 1);
+        self.column_position = core::cmp::min(col, BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    //actually lay a glyph down at the cursor, wrapping to a new line first
+    //if we have run past the right edge
+    fn put_byte(&mut self, byte: u8) {
+        if self.column_position >= BUFFER_WIDTH {
+            self.new_line();
+        }
+        let row = self.row_position;
+        let col = self.column_position;
+        let color_code = self.color_code;
+        let cell = ScreenChar {
+            ascii_character: byte,
+            color_code,
+        };
+        //keep the live-line mirror in sync when we are writing the bottom row
+        //(cursor positioning may send us elsewhere on screen)
+        if row == BUFFER_HEIGHT - 1 {
+            self.current_row[col] = cell;
+        }
+        self.buffer.chars[row][col].write(cell);
+        self.column_position += 1;
+    }
+
+    //dispatch a completed CSI sequence on its final byte
+    fn handle_csi(&mut self, final_byte: u8) {
+        match final_byte {
+            b'm' => self.select_graphic_rendition(),
+            b'H' | b'f' => {
+                let mut p = [0u16; 2];
+                let n = self.parse_params(&mut p);
+                //CSI coordinates are 1-based, default to the home cell
+                let row = if n >= 1 && p[0] > 0 { (p[0] - 1) as usize } else { 0 };
+                let col = if n >= 2 && p[1] > 0 { (p[1] - 1) as usize } else { 0 };
+                self.set_position(row, col);
             }
+            b'J' => self.clear_screen(),
+            b'K' => self.clear_line(),
+            //unknown finals are ignored rather than printed
+            _ => {}
         }
     }
 
-    pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => {
-                    self.write_byte(byte)
+    //apply an SGR (`m`) sequence: map the 30-37/90-97 and 40-47/100-107
+    //codes onto the Color enum and rebuild color_code, with 0 resetting to
+    //the default yellow-on-black
+    fn select_graphic_rendition(&mut self) {
+        let mut p = [0u16; 8];
+        let n = self.parse_params(&mut p);
+        for &code in &p[..n] {
+            match code {
+                0 => {
+                    self.foreground = Color::Yellow;
+                    self.background = Color::Black;
+                    self.blink = false;
                 }
-                _ => self.write_byte(0xfe)
+                5 => self.blink = true,
+                25 => self.blink = false,
+                30..=37 => self.foreground = ansi_to_color(code - 30, false),
+                90..=97 => self.foreground = ansi_to_color(code - 90, true),
+                40..=47 => self.background = ansi_to_color(code - 40, false),
+                100..=107 => self.background = ansi_to_color(code - 100, true),
+                _ => {}
             }
         }
+        self.apply_color();
     }
 
-    //we iterate over each char and move each char one row up
-    fn new_line(&mut self ) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row-1][col].write(character);
+    //rebuild color_code from the current foreground/background/blink state
+    fn apply_color(&mut self) {
+        self.color_code = ColorCode::new(self.foreground, self.background, self.blink);
+    }
+
+    //set both the foreground and background for subsequent output
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.foreground = foreground;
+        self.background = background;
+        self.apply_color();
+    }
+
+    pub fn set_foreground(&mut self, foreground: Color) {
+        self.foreground = foreground;
+        self.apply_color();
+    }
+
+    pub fn set_background(&mut self, background: Color) {
+        self.background = background;
+        self.apply_color();
+    }
+
+    //restore the default yellow-on-black, non-blinking colour
+    pub fn reset_color(&mut self) {
+        self.foreground = Color::Yellow;
+        self.background = Color::Black;
+        self.blink = false;
+        self.apply_color();
+    }
+
+    //toggle the VGA blink attribute. note this reuses the top background-colour
+    //bit, so while blinking is on the background is restricted to the first
+    //eight colours.
+    pub fn set_blink(&mut self, blink: bool) {
+        self.blink = blink;
+        self.apply_color();
+    }
+
+    //split the accumulated parameter bytes on ';' into numeric values;
+    //an empty list yields a single 0 so a bare `ESC[m` resets
+    fn parse_params(&self, out: &mut [u16]) -> usize {
+        let mut n = 0;
+        let mut cur: u16 = 0;
+        for &b in &self.params[..self.params_len] {
+            if b == b';' {
+                if n < out.len() {
+                    out[n] = cur;
+                    n += 1;
+                }
+                cur = 0;
+            } else if b.is_ascii_digit() {
+                cur = cur.wrapping_mul(10).wrapping_add((b - b'0') as u16);
             }
         }
-        self.clear_row(BUFFER_HEIGHT-1);
+        if n < out.len() {
+            out[n] = cur;
+            n += 1;
+        }
+        n
+    }
+
+    //`ESC[J` blanks the whole visible window. the retained history would
+    //otherwise be repainted onto the top rows by render(), so we drop it and
+    //start a fresh blank live line.
+    fn clear_screen(&mut self) {
+        self.history_start = 0;
+        self.history_len = 0;
+        self.view_offset = 0;
+        self.current_row = self.blank_row();
+        self.row_position = BUFFER_HEIGHT - 1;
         self.column_position = 0;
+        self.render();
+    }
+
+    //`ESC[K` blanks the line the cursor sits on, leaving the cursor column
+    //untouched
+    fn clear_line(&mut self) {
+        let row = self.row_position;
+        let blank = self.blank_row();
+        for col in 0..BUFFER_WIDTH {
+            self.buffer.chars[row][col].write(blank[col]);
+        }
+        if row == BUFFER_HEIGHT - 1 {
+            self.current_row = blank;
+        }
+    }
+
+    //the VGA font is code page 437, not ASCII, so decode the string as UTF-8
+    //`char`s and translate each code point to its CP437 byte. ASCII passes
+    //through unchanged (and still feeds the escape-sequence state machine),
+    //while unmappable characters fall back to 0xfe.
+    pub fn write_string(&mut self, s: &str) {
+        for c in s.chars() {
+            if (c as u32) < 0x80 {
+                self.write_byte(c as u8);
+            } else {
+                self.write_byte(cp437(c));
+            }
+        }
+    }
 
+    //the completed bottom line is pushed into the scrollback rather than
+    //copied up in place; we then re-render the visible window and start a
+    //fresh blank live row at the bottom
+    fn new_line(&mut self) {
+        let completed = self.current_row;
+        self.push_history(completed);
+        self.current_row = self.blank_row();
+        self.column_position = 0;
+        self.row_position = BUFFER_HEIGHT - 1;
+        self.view_offset = 0;
+        self.render();
+        self.update_cursor();
     }
 
-    //method clears a row by overwriting its char's with space 
-    fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
+    //a row of spaces in the current colour
+    fn blank_row(&self) -> Row {
+        [ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
-        };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        }; BUFFER_WIDTH]
+    }
+
+    //append a finished row to the history ring, dropping the oldest once full
+    fn push_history(&mut self, row: Row) {
+        let slot = (self.history_start + self.history_len) % SCROLLBACK_ROWS;
+        self.history[slot] = row;
+        if self.history_len < SCROLLBACK_ROWS {
+            self.history_len += 1;
+        } else {
+            self.history_start = (self.history_start + 1) % SCROLLBACK_ROWS;
+        }
+    }
+
+    //the i-th oldest retained history row
+    fn history_row(&self, i: usize) -> Row {
+        self.history[(self.history_start + i) % SCROLLBACK_ROWS]
+    }
+
+    //redraw all BUFFER_HEIGHT visible rows from history plus the live line,
+    //honouring the current scroll offset. logical line `history_len` is the
+    //live row; anything below 0 is blank padding.
+    fn render(&mut self) {
+        let bottom = (self.history_len - self.view_offset) as isize;
+        let top = bottom - (BUFFER_HEIGHT as isize - 1);
+        for r in 0..BUFFER_HEIGHT {
+            let logical = top + r as isize;
+            let row = if logical < 0 {
+                self.blank_row()
+            } else if (logical as usize) < self.history_len {
+                self.history_row(logical as usize)
+            } else {
+                self.current_row
+            };
+            for col in 0..BUFFER_WIDTH {
+                self.buffer.chars[r][col].write(row[col]);
+            }
+        }
+    }
+
+    //shift the viewport up into history. the offset is capped so a full
+    //screenful of the oldest history stays visible rather than scrolling the
+    //content off the bottom.
+    pub fn scroll_up(&mut self, lines: usize) {
+        let max_offset = self.history_len.saturating_sub(BUFFER_HEIGHT - 1);
+        self.view_offset = core::cmp::min(self.view_offset + lines, max_offset);
+        self.render();
+    }
+
+    //shift the viewport back down toward live output
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.view_offset = self.view_offset.saturating_sub(lines);
+        self.render();
+    }
+
+    //snap the viewport back to the live bottom if it was scrolled away
+    pub fn scroll_to_bottom(&mut self) {
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.render();
         }
     }
 
 
+}
 
-    
+//map an ANSI colour index (0-7) onto the VGA Color enum, picking the bright
+//variant for the 90-97/100-107 codes
+fn ansi_to_color(idx: u16, bright: bool) -> Color {
+    match (idx, bright) {
+        (0, false) => Color::Black,
+        (1, false) => Color::Red,
+        (2, false) => Color::Green,
+        (3, false) => Color::Brown,
+        (4, false) => Color::Blue,
+        (5, false) => Color::Magenta,
+        (6, false) => Color::Cyan,
+        (7, false) => Color::LightGray,
+        (0, true) => Color::DarkGray,
+        (1, true) => Color::LightRed,
+        (2, true) => Color::LightGreen,
+        (3, true) => Color::Yellow,
+        (4, true) => Color::LightBlue,
+        (5, true) => Color::Pink,
+        (6, true) => Color::LightCyan,
+        (7, true) => Color::White,
+        _ => Color::LightGray,
+    }
+}
 
+//translate a Unicode code point to its code page 437 byte. only the upper
+//half (0x80..=0xff) needs a table; ASCII is handled by the caller. characters
+//the font cannot represent fall back to 0xfe.
+fn cp437(c: char) -> u8 {
+    match c {
+        'Ç' => 0x80, 'ü' => 0x81, 'é' => 0x82, 'â' => 0x83,
+        'ä' => 0x84, 'à' => 0x85, 'å' => 0x86, 'ç' => 0x87,
+        'ê' => 0x88, 'ë' => 0x89, 'è' => 0x8a, 'ï' => 0x8b,
+        'î' => 0x8c, 'ì' => 0x8d, 'Ä' => 0x8e, 'Å' => 0x8f,
+        'É' => 0x90, 'æ' => 0x91, 'Æ' => 0x92, 'ô' => 0x93,
+        'ö' => 0x94, 'ò' => 0x95, 'û' => 0x96, 'ù' => 0x97,
+        'ÿ' => 0x98, 'Ö' => 0x99, 'Ü' => 0x9a, '¢' => 0x9b,
+        '£' => 0x9c, '¥' => 0x9d, '₧' => 0x9e, 'ƒ' => 0x9f,
+        'á' => 0xa0, 'í' => 0xa1, 'ó' => 0xa2, 'ú' => 0xa3,
+        'ñ' => 0xa4, 'Ñ' => 0xa5, 'ª' => 0xa6, 'º' => 0xa7,
+        '¿' => 0xa8, '⌐' => 0xa9, '¬' => 0xaa, '½' => 0xab,
+        '¼' => 0xac, '¡' => 0xad, '«' => 0xae, '»' => 0xaf,
+        '░' => 0xb0, '▒' => 0xb1, '▓' => 0xb2, '│' => 0xb3,
+        '┤' => 0xb4, '╡' => 0xb5, '╢' => 0xb6, '╖' => 0xb7,
+        '╕' => 0xb8, '╣' => 0xb9, '║' => 0xba, '╗' => 0xbb,
+        '╝' => 0xbc, '╜' => 0xbd, '╛' => 0xbe, '┐' => 0xbf,
+        '└' => 0xc0, '┴' => 0xc1, '┬' => 0xc2, '├' => 0xc3,
+        '─' => 0xc4, '┼' => 0xc5, '╞' => 0xc6, '╟' => 0xc7,
+        '╚' => 0xc8, '╔' => 0xc9, '╩' => 0xca, '╦' => 0xcb,
+        '╠' => 0xcc, '═' => 0xcd, '╬' => 0xce, '╧' => 0xcf,
+        '╨' => 0xd0, '╤' => 0xd1, '╥' => 0xd2, '╙' => 0xd3,
+        '╘' => 0xd4, '╒' => 0xd5, '╓' => 0xd6, '╫' => 0xd7,
+        '╪' => 0xd8, '┘' => 0xd9, '┌' => 0xda, '█' => 0xdb,
+        '▄' => 0xdc, '▌' => 0xdd, '▐' => 0xde, '▀' => 0xdf,
+        'α' => 0xe0, 'ß' => 0xe1, 'Γ' => 0xe2, 'π' => 0xe3,
+        'Σ' => 0xe4, 'σ' => 0xe5, 'µ' => 0xe6, 'τ' => 0xe7,
+        'Φ' => 0xe8, 'Θ' => 0xe9, 'Ω' => 0xea, 'δ' => 0xeb,
+        '∞' => 0xec, 'φ' => 0xed, 'ε' => 0xee, '∩' => 0xef,
+        '≡' => 0xf0, '±' => 0xf1, '≥' => 0xf2, '≤' => 0xf3,
+        '⌠' => 0xf4, '⌡' => 0xf5, '÷' => 0xf6, '≈' => 0xf7,
+        '°' => 0xf8, '∙' => 0xf9, '·' => 0xfa, '√' => 0xfb,
+        'ⁿ' => 0xfc, '²' => 0xfd, '■' => 0xfe, '\u{a0}' => 0xff,
+        _ => 0xfe,
+    }
 }
 
 //to print different types we can support rust's formatting macros such as write! and writeln!
@@ -146,19 +569,31 @@ impl fmt::Write for Writer {
 //it will be useless since its immutable
 //we can use mutable static with spinlock to prevent race conditions
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new( Writer { 
-        column_position: 0, 
-        color_code: ColorCode::new(Color::Yellow,Color::Black),
+    pub static ref WRITER: Mutex<Writer> = Mutex::new( Writer {
+        column_position: 0,
+        row_position: BUFFER_HEIGHT - 1,
+        color_code: ColorCode::new(Color::Yellow,Color::Black,false),
+        foreground: Color::Yellow,
+        background: Color::Black,
+        parse_state: ParseState::Ground,
+        params: [0; 16],
+        params_len: 0,
+        blink: false,
+        current_row: [BLANK; BUFFER_WIDTH],
+        history: [[BLANK; BUFFER_WIDTH]; SCROLLBACK_ROWS],
+        history_start: 0,
+        history_len: 0,
+        view_offset: 0,
         buffer: unsafe {
             &mut *(0xb8000 as *mut Buffer)
-        } 
+        }
     });
 }
 
 
 #[macro_export]
 macro_rules! print {
-    //tt tokentree matches anything.  
+    //tt tokentree matches anything.
     ($($x: tt)*) => (
         $crate::vga_buffer::_print(format_args!($($x)*))
     );
@@ -167,7 +602,7 @@ macro_rules! print {
 #[macro_export]
 macro_rules! println {
     //tt tokentree matches anything.
-    () => ($crate::print!("\n"));  
+    () => ($crate::print!("\n"));
     ($($x: tt)*) => (
         $crate::print!("{}\n",format_args!($($x)*))
     );
@@ -178,4 +613,20 @@ macro_rules! println {
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     WRITER.lock().write_fmt(args).unwrap();
-}
\ No newline at end of file
+}
+
+
+//run `f` with the global writer set to the given colours, restoring whatever
+//colour (and blink) state was in effect beforehand. the lock is released
+//around `f` so the closure can itself print through WRITER.
+pub fn with_color<F: FnOnce()>(foreground: Color, background: Color, f: F) {
+    let (prev_fg, prev_bg, prev_blink) = {
+        let writer = WRITER.lock();
+        (writer.foreground, writer.background, writer.blink)
+    };
+    WRITER.lock().set_color(foreground, background);
+    f();
+    let mut writer = WRITER.lock();
+    writer.set_color(prev_fg, prev_bg);
+    writer.set_blink(prev_blink);
+}